@@ -1,18 +1,36 @@
 //! Mira -- Git mirrors from a JSON config file.
 
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::path;
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lettre::Transport;
 
 const MIRROR_REMOTE_NAME: &str = "mirror";
 
+const DEFAULT_SERVE_PORT: u16 = 8980;
+
+/// How often a mirror without an explicit `sync_every` is re-synced in daemon mode.
+const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+
 fn main() {
     let matches = clap::App::new("Mira")
         .setting(clap::AppSettings::ArgRequiredElseHelp)
         .arg(clap::Arg::with_name("config")
-             .short("c").long("config").takes_value(true).required(true))
+             .short("c").long("config").takes_value(true).required(true).global(true))
+        .subcommand(clap::SubCommand::with_name("serve")
+             .about("Start a webhook listener and mirror repositories as pushes come in")
+             .arg(clap::Arg::with_name("port")
+                  .short("p").long("port").takes_value(true)))
+        .subcommand(clap::SubCommand::with_name("daemon")
+             .about("Stay resident and re-sync each mirror on its own schedule"))
         .get_matches();
     let config_file = matches.value_of("config").unwrap();
     let config_text = match load_file(&path::Path::new(config_file)) {
@@ -23,9 +41,198 @@ fn main() {
         Ok(config) => config,
         Err(e) => { eprintln!("{:?}", e); process::exit(1) }
     };
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let port = serve_matches.value_of("port")
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(DEFAULT_SERVE_PORT);
+        serve(&root_config, port);
+    }
+    if matches.subcommand_matches("daemon").is_some() {
+        daemon(&root_config);
+    }
     process::exit(if process_root_config(&root_config) { 0 } else { 1 });
 }
 
+/// Stay resident, re-running each mirror on its own `sync_every` schedule instead of exiting.
+///
+/// Every iteration runs the mirrors that are currently due, then sleeps until the next one comes
+/// due. A mirror that has never run is due immediately.
+fn daemon(root_config: &RootConfig) -> ! {
+    let workspace = path::Path::new(&root_config.workspace);
+    if !workspace.is_dir() {
+        if let Err(e) = fs::create_dir_all(&workspace) {
+            eprintln!("Failed to create workspace directory: {}.", e);
+            process::exit(1);
+        }
+    }
+    let mut last_run: HashMap<(String, String), Instant> = HashMap::new();
+    loop {
+        let now = Instant::now();
+        let mut next_wake: Option<Instant> = None;
+        for config in &root_config.configurations {
+            let mut config_path = workspace.to_path_buf();
+            config_path.push(&config.name);
+            for mirror in &config.mirrors {
+                let interval = sync_interval(root_config, mirror);
+                let key = (config.name.clone(), mirror.name.clone());
+                let due_at = last_run.get(&key).map(|last| *last + interval);
+                if due_at.map_or(true, |due_at| now >= due_at) {
+                    if let Err(e) = fs::create_dir_all(&config_path) {
+                        eprintln!("Failed to create configuration directory: {}.", e);
+                        continue
+                    }
+                    match mirror_repo(&mirror.name, &mirror.src, &mirror.dest.as_vec(), &config_path, mirror.credentials.as_ref(), mirror.lfs) {
+                        Ok((MirrorResult::Success, _)) => println!("{} mirrored successfully.", mirror.name),
+                        Ok(_) => eprintln!("Failed to mirror {}.", mirror.name),
+                        Err(e) => eprintln!("An error occured during {} mirroring: {}", mirror.name, e),
+                    }
+                    last_run.insert(key, now);
+                    next_wake = Some(next_wake.map_or(now + interval, |wake| wake.min(now + interval)));
+                } else if let Some(due_at) = due_at {
+                    next_wake = Some(next_wake.map_or(due_at, |wake| wake.min(due_at)));
+                }
+            }
+        }
+        let wake_at = next_wake.unwrap_or_else(|| now + DEFAULT_SYNC_INTERVAL);
+        let sleep_for = wake_at.saturating_duration_since(Instant::now());
+        println!("Next sync in {:?}.", sleep_for);
+        thread::sleep(sleep_for);
+    }
+}
+
+/// The effective `sync_every` interval for `mirror`, falling back to the root config's, then to
+/// `DEFAULT_SYNC_INTERVAL` if neither is set.
+///
+/// Each level is parsed on its own: an unparseable `mirror.sync_every` doesn't cut off a valid
+/// root-level value, and either level failing to parse is logged instead of silently swallowed.
+fn sync_interval(root_config: &RootConfig, mirror: &Mirror) -> Duration {
+    if let Some(value) = &mirror.sync_every {
+        match parse_duration(value) {
+            Some(duration) => return duration,
+            None => eprintln!("Mirror {}: invalid sync_every {:?}, falling back.", mirror.name, value),
+        }
+    }
+    if let Some(value) = &root_config.sync_every {
+        match parse_duration(value) {
+            Some(duration) => return duration,
+            None => eprintln!("Invalid root sync_every {:?}, falling back to default.", value),
+        }
+    }
+    DEFAULT_SYNC_INTERVAL
+}
+
+/// Parse a duration string like `"15m"`, `"1h"`, or `"2d"` (seconds/minutes/hours/days).
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split_at = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split_at);
+    let amount: u64 = amount.parse().ok()?;
+    let seconds = match unit {
+        "s" => amount,
+        "m" => amount * 60,
+        "h" => amount * 3600,
+        "d" => amount * 86400,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+/// Listen for push webhooks and mirror only the repository that was pushed to.
+///
+/// This never returns: each accepted request is parsed as a `PushWebhook`, matched against the
+/// configured mirrors by `full_name` (falling back to `src`), and mirrored in place. Unlike
+/// `process_root_config`, a single push only triggers the one matching mirror instead of a full
+/// pass over every configuration.
+fn serve(root_config: &RootConfig, port: u16) -> ! {
+    let workspace = path::Path::new(&root_config.workspace);
+    if !workspace.is_dir() {
+        if let Err(e) = fs::create_dir_all(&workspace) {
+            eprintln!("Failed to create workspace directory: {}.", e);
+            process::exit(1);
+        }
+    }
+    let server = match tiny_http::Server::http(("0.0.0.0", port)) {
+        Ok(server) => server,
+        Err(e) => { eprintln!("Failed to start webhook server on port {}: {}", port, e); process::exit(1) }
+    };
+    println!("Listening for push webhooks on port {}.", port);
+    loop {
+        let mut request = match server.recv() {
+            Ok(request) => request,
+            Err(e) => { eprintln!("Failed to receive webhook request: {}", e); continue }
+        };
+        let mut body = String::new();
+        if let Err(e) = request.as_reader().read_to_string(&mut body) {
+            eprintln!("Failed to read webhook body: {}", e);
+            let _ = request.respond(tiny_http::Response::empty(400));
+            continue
+        }
+        let webhook: PushWebhook = match serde_json::from_str(&body) {
+            Ok(webhook) => webhook,
+            Err(e) => {
+                eprintln!("Failed to parse webhook body: {}", e);
+                let _ = request.respond(tiny_http::Response::empty(400));
+                continue
+            }
+        };
+        match find_mirror_for_webhook(root_config, &webhook) {
+            Some((config, mirror)) => {
+                let mut config_path = workspace.to_path_buf();
+                config_path.push(&config.name);
+                if let Err(e) = fs::create_dir_all(&config_path) {
+                    eprintln!("Failed to create configuration directory: {}.", e);
+                    let _ = request.respond(tiny_http::Response::empty(500));
+                    continue
+                }
+                match mirror_repo(&mirror.name, &mirror.src, &mirror.dest.as_vec(), &config_path, mirror.credentials.as_ref(), mirror.lfs) {
+                    Ok((MirrorResult::Success, _)) => println!("{} mirrored successfully.", mirror.name),
+                    Ok(_) => eprintln!("Failed to mirror {} after webhook.", mirror.name),
+                    Err(e) => eprintln!("An error occured during {} mirroring: {}", mirror.name, e),
+                }
+                let _ = request.respond(tiny_http::Response::empty(200));
+            }
+            None => {
+                eprintln!("No mirror configured for repository {}.", webhook.repository.full_name);
+                let _ = request.respond(tiny_http::Response::empty(404));
+            }
+        }
+    }
+}
+
+/// Find the configuration and mirror matching a push webhook, by `full_name` then by `src`.
+fn find_mirror_for_webhook<'a>(
+    root_config: &'a RootConfig,
+    webhook: &PushWebhook,
+) -> Option<(&'a Configuration, &'a Mirror)> {
+    for config in &root_config.configurations {
+        for mirror in &config.mirrors {
+            if mirror.full_name.as_deref() == Some(webhook.repository.full_name.as_str()) {
+                return Some((config, mirror))
+            }
+        }
+    }
+    for config in &root_config.configurations {
+        for mirror in &config.mirrors {
+            if mirror.src == webhook.repository.clone_url {
+                return Some((config, mirror))
+            }
+        }
+    }
+    None
+}
+
+/// Gitea/GitHub-style push webhook payload, trimmed to the fields Mira needs.
+#[derive(Debug, serde::Deserialize)]
+struct PushWebhook {
+    repository: PushWebhookRepository,
+}
+
+/// The `repository` object of a push webhook payload.
+#[derive(Debug, serde::Deserialize)]
+struct PushWebhookRepository {
+    full_name: String,
+    clone_url: String,
+}
+
 fn load_file(path: &path::Path) -> Result<String, io::Error> {
     let mut file = fs::File::open(path)?;
     let mut contents = String::new();
@@ -38,6 +245,72 @@ fn load_file(path: &path::Path) -> Result<String, io::Error> {
 struct RootConfig {
     workspace: String,
     configurations: Vec<Configuration>,
+    /// Default re-sync interval for `daemon` mode (e.g. `"15m"`, `"1h"`), used by mirrors that
+    /// don't set their own.
+    #[serde(default)]
+    sync_every: Option<String>,
+    /// Email notification of mirror results, opt-in.
+    #[serde(default)]
+    notify: Option<NotifyConfig>,
+}
+
+/// SMTP settings for the opt-in post-run notification email.
+#[derive(Debug, serde::Deserialize)]
+struct NotifyConfig {
+    smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    smtp_port: u16,
+    from: String,
+    recipients: Vec<String>,
+}
+
+fn default_smtp_port() -> u16 { 25 }
+
+/// Email `notify.recipients` a summary of this run's mirror results.
+///
+/// Sends one message per recipient and keeps going past a delivery failure, logging it, so that
+/// one bad address doesn't swallow the rest of the report.
+fn send_notification(notify: &NotifyConfig, report: &[MirrorReport]) {
+    let body = render_report(report);
+    let subject = format!("Mira: {} mirror(s) processed", report.len());
+    let from: lettre::message::Mailbox = match notify.from.parse() {
+        Ok(from) => from,
+        Err(e) => { eprintln!("Invalid notify.from address {}: {}", notify.from, e); return }
+    };
+    let mailer = lettre::SmtpTransport::builder_dangerous(&notify.smtp_host)
+        .port(notify.smtp_port)
+        .build();
+    for recipient in &notify.recipients {
+        let to: lettre::message::Mailbox = match recipient.parse() {
+            Ok(to) => to,
+            Err(e) => { eprintln!("Invalid notify recipient {}: {}", recipient, e); continue }
+        };
+        let email = match lettre::Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(&subject)
+            .body(body.clone())
+        {
+            Ok(email) => email,
+            Err(e) => { eprintln!("Failed to build notification email for {}: {}", recipient, e); continue }
+        };
+        if let Err(e) = mailer.send(&email) {
+            eprintln!("Failed to send notification to {}: {}", recipient, e);
+        }
+    }
+}
+
+/// Render the per-mirror report as a plain-text email body.
+fn render_report(report: &[MirrorReport]) -> String {
+    let mut body = String::new();
+    for entry in report {
+        body.push_str(&format!("{}: {}", entry.repo, entry.outcome));
+        if let Some(detail) = &entry.detail {
+            body.push_str(&format!(" ({})", detail));
+        }
+        body.push('\n');
+    }
+    body
 }
 
 /// Server configuration.
@@ -52,7 +325,102 @@ struct Configuration {
 struct Mirror {
     name: String,
     src: String,
-    dest: String,
+    /// One or more push targets. All of them share `credentials` below, so fanning out to
+    /// forges that need different credentials (e.g. GitHub and GitLab with separate tokens)
+    /// isn't supported by a single `Mirror` -- split it into one `Mirror` per credential instead.
+    dest: OneOrMany<String>,
+    /// `owner/repo`-style identifier used to match incoming push webhooks, when `serve`ing.
+    #[serde(default)]
+    full_name: Option<String>,
+    /// Re-sync interval for `daemon` mode (e.g. `"15m"`, `"1h"`), overriding the root config's.
+    #[serde(default)]
+    sync_every: Option<String>,
+    /// Authentication to use for both `src` and every `dest` of this mirror -- one credential for
+    /// the whole mirror, not one per destination.
+    #[serde(default)]
+    credentials: Option<Credentials>,
+    /// Also mirror Git LFS objects, not just the pointer files `clone`/`push --mirror` move.
+    #[serde(default)]
+    lfs: bool,
+}
+
+/// A way to authenticate outgoing git operations for a `Mirror`.
+///
+/// The secret fields (`password`, `token` and `passphrase`) may hold a literal secret, or
+/// `env:VAR_NAME` to read it from the environment at mirror time instead of storing it in the
+/// config file. `username` and `private_key_path` are always taken literally.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Credentials {
+    UserPass { username: String, password: String },
+    Token { token: String },
+    SshKey { username: String, private_key_path: String, passphrase: Option<String> },
+}
+
+/// Resolve a credential value, reading it from the environment if prefixed with `env:`.
+fn resolve_secret(value: &str) -> String {
+    match value.strip_prefix("env:") {
+        Some(var_name) => env::var(var_name).unwrap_or_default(),
+        None => value.to_string(),
+    }
+}
+
+/// Build the `RemoteCallbacks` used for every fetch/push, supplying credentials on demand.
+///
+/// libgit2 re-invokes the credentials callback whenever the server rejects the credential, so
+/// without attempt tracking a wrong token/password would make `fetch`/`push` retry forever. We
+/// only ever have the one credential to offer, so give up after the first attempt instead.
+fn remote_callbacks(credentials: Option<&Credentials>) -> git2::RemoteCallbacks {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    let mut attempted = false;
+    callbacks.credentials(move |_url, username_from_url, allowed_types| {
+        // libgit2 probes for just a username first on SSH URLs with none embedded (e.g.
+        // `ssh://host/repo`); answer that separately so it doesn't spend our one credential
+        // attempt before the real `SSH_KEY` request comes in.
+        if allowed_types.contains(git2::CredentialType::USERNAME) {
+            return git2::Cred::username(username_from_url.unwrap_or("git"));
+        }
+        if attempted {
+            return Err(git2::Error::from_str("credential was rejected; not retrying"));
+        }
+        attempted = true;
+        match credentials {
+            Some(Credentials::UserPass { username, password })
+                if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) =>
+                git2::Cred::userpass_plaintext(username, &resolve_secret(password)),
+            Some(Credentials::Token { token })
+                if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) =>
+                git2::Cred::userpass_plaintext("x-access-token", &resolve_secret(token)),
+            Some(Credentials::SshKey { username, private_key_path, passphrase })
+                if allowed_types.contains(git2::CredentialType::SSH_KEY) => {
+                let passphrase = passphrase.as_ref().map(|p| resolve_secret(p));
+                git2::Cred::ssh_key(username, None, path::Path::new(private_key_path), passphrase.as_deref())
+            }
+            Some(_) => Err(git2::Error::from_str("configured credentials don't match a type this remote accepts")),
+            None => git2::Cred::default().or_else(|_| {
+                git2::Cred::username(username_from_url.unwrap_or("git"))
+            }),
+        }
+    });
+    callbacks
+}
+
+/// A config value that can be given as either a single item or a list of items.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl OneOrMany<String> {
+    /// Return the contained strings as a vector of `&str`, in declaration order.
+    fn as_vec(&self) -> Vec<&str> {
+        match self {
+            OneOrMany::One(item) => vec!(item.as_str()),
+            OneOrMany::Many(items) => items.iter().map(String::as_str).collect(),
+        }
+    }
 }
 
 /// Process the Mira configuration file, return true on complete success.
@@ -67,16 +435,21 @@ fn process_root_config(root_config: &RootConfig) -> bool {
     }
     // Process each configuration, even if some of them fail.
     let mut complete_success = true;
+    let mut report = Vec::new();
     for config in &root_config.configurations {
-        if let Err(e) = process_config(config, workspace) {
+        if let Err(e) = process_config(config, workspace, &mut report) {
             eprintln!("An error occured with configuration {}: {}", config.name, e);
             complete_success = false;
         }
     }
+    if let Some(notify) = &root_config.notify {
+        send_notification(notify, &report);
+    }
     complete_success
 }
 
 /// Result of a mirror operation.
+#[derive(Debug, Clone, Copy)]
 enum MirrorResult {
     Success,
     CloneFailed,
@@ -85,12 +458,36 @@ enum MirrorResult {
     PushFailed,
 }
 
+impl fmt::Display for MirrorResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            MirrorResult::Success => "success",
+            MirrorResult::CloneFailed => "clone failed",
+            MirrorResult::FetchFailed => "fetch failed",
+            MirrorResult::RemotesError => "remote error",
+            MirrorResult::PushFailed => "push failed",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// One mirror's outcome, collected across a run for the optional notification email.
+struct MirrorReport {
+    repo: String,
+    outcome: MirrorResult,
+    detail: Option<String>,
+}
+
 /// Process mirrors of this server configuration.
 ///
 /// If an IO error is met when preparing for the mirroring, this function returns early with this
 /// error. After that, all mirrors in `config` are processed, and the function returns true only if
 /// every mirror completes succesfully.
-fn process_config(config: &Configuration, workspace: &path::Path) -> Result<bool, io::Error> {
+fn process_config(
+    config: &Configuration,
+    workspace: &path::Path,
+    report: &mut Vec<MirrorReport>,
+) -> Result<bool, io::Error> {
     println!("Processing config {}.", config.name);
     // Move into the configuration directory.
     let mut config_path = workspace.to_path_buf();
@@ -101,23 +498,16 @@ fn process_config(config: &Configuration, workspace: &path::Path) -> Result<bool
     // Mirror each repository in the configuration.
     let mut complete_success = true;
     for mirror in &config.mirrors {
-        match mirror_repo(&mirror.name, &mirror.src, &mirror.dest, &config_path) {
-            Ok(MirrorResult::Success) => { println!("{} mirrored successfully.", mirror.name); },
-            Ok(MirrorResult::CloneFailed) => {
-                println!("Failed to clone {}.", mirror.name);
-                complete_success = false;
-            },
-            Ok(MirrorResult::FetchFailed) => {
-                println!("Failed to fetch changes for {}.", mirror.name);
-                complete_success = false;
-            },
-            Ok(MirrorResult::RemotesError) => {
-                println!("Failed to process remotes for {}.", mirror.name);
-                complete_success = false;
-            },
-            Ok(MirrorResult::PushFailed) => {
-                println!("Failed to push {}.", mirror.name);
-                complete_success = false;
+        match mirror_repo(&mirror.name, &mirror.src, &mirror.dest.as_vec(), &config_path, mirror.credentials.as_ref(), mirror.lfs) {
+            Ok((outcome, detail)) => {
+                match outcome {
+                    MirrorResult::Success => println!("{} mirrored successfully.", mirror.name),
+                    MirrorResult::CloneFailed => { println!("Failed to clone {}.", mirror.name); complete_success = false; },
+                    MirrorResult::FetchFailed => { println!("Failed to fetch changes for {}.", mirror.name); complete_success = false; },
+                    MirrorResult::RemotesError => { println!("Failed to process remotes for {}.", mirror.name); complete_success = false; },
+                    MirrorResult::PushFailed => { println!("Failed to push {}.", mirror.name); complete_success = false; },
+                }
+                report.push(MirrorReport { repo: mirror.name.clone(), outcome, detail });
             },
             Err(e) => {
                 eprintln!("An error occured during {} mirroring: {}", mirror.name, e);
@@ -128,122 +518,190 @@ fn process_config(config: &Configuration, workspace: &path::Path) -> Result<bool
     Ok(complete_success)
 }
 
-/// Mirror a repository from `src_url` to `dest_url`.
+/// Mirror a repository from `src_url` to every URL in `dest_urls`.
 ///
 /// This function assumes that the current work directory is the workspace,
 /// so that a directory named `name` can be used to clone and/or push from.
+/// Each destination gets its own remote (`mirror-0`, `mirror-1`, ...) so a push failure on one
+/// destination is reported without preventing the others from being attempted.
 fn mirror_repo(
     name: &str,
     src_url: &str,
-    dest_url: &str,
-    path: &path::Path
-) -> Result<MirrorResult, io::Error> {
+    dest_urls: &[&str],
+    path: &path::Path,
+    credentials: Option<&Credentials>,
+    lfs: bool,
+) -> Result<(MirrorResult, Option<String>), io::Error> {
     let mut repo_path = path.to_path_buf();
     repo_path.push(name);
     // Ensure the repository is cloned and up to date.
-    if !repo_path.exists() {
-        if let Some(e) = check_git_return(&clone(src_url, path, name), MirrorResult::CloneFailed) {
-            return Ok(e)
+    let repo = if !repo_path.exists() {
+        match clone(src_url, &repo_path, credentials) {
+            Ok(repo) => repo,
+            Err(e) => { log_git_error("clone", &e); return Ok((MirrorResult::CloneFailed, Some(e.message().to_string()))) }
         }
     } else {
-        if let Some(e) = check_git_return(&fetch(&repo_path), MirrorResult::FetchFailed) {
-            return Ok(e)
+        let repo = match git2::Repository::open_bare(&repo_path) {
+            Ok(repo) => repo,
+            Err(e) => { log_git_error("open", &e); return Ok((MirrorResult::FetchFailed, Some(e.message().to_string()))) }
+        };
+        if let Err(e) = fetch(&repo, credentials) {
+            log_git_error("fetch", &e);
+            return Ok((MirrorResult::FetchFailed, Some(e.message().to_string())))
+        }
+        repo
+    };
+    // A plain fetch only moves the LFS pointer files; pull the objects they point to as well.
+    if lfs {
+        if let Err(e) = lfs_fetch(&repo_path, "origin", credentials) {
+            eprintln!("Git LFS fetch failed: {}", e);
+            return Ok((MirrorResult::FetchFailed, Some(e.to_string())))
         }
     }
-    // Ensure the mirror remote is available.
-    let remotes = match get_remotes(&repo_path) {
-        Some(remotes) => remotes,
-        None => return Ok(MirrorResult::RemotesError)
+    // Ensure every destination has its own mirror remote.
+    let remotes = match get_remotes(&repo) {
+        Ok(remotes) => remotes,
+        Err(e) => { log_git_error("remote listing", &e); return Ok((MirrorResult::RemotesError, Some(e.message().to_string()))) }
     };
-    if !remotes.contains(&MIRROR_REMOTE_NAME.to_string()) {
-        if let Some(e) = check_git_return(
-            &add_mirror_remote(&repo_path, dest_url),
-            MirrorResult::RemotesError
-        ) {
-            return Ok(e)
+    // Push to every destination, continuing past failures so one bad target doesn't mask the others.
+    let mut push_errors = Vec::new();
+    for (index, dest_url) in dest_urls.iter().enumerate() {
+        let remote_name = format!("{}-{}", MIRROR_REMOTE_NAME, index);
+        if !remotes.contains(&remote_name) {
+            if let Err(e) = add_mirror_remote(&repo, &remote_name, dest_url) {
+                log_git_error("remote add", &e);
+                return Ok((MirrorResult::RemotesError, Some(e.message().to_string())))
+            }
+        }
+        if let Err(e) = push(&repo, &remote_name, credentials) {
+            log_git_error(&format!("push to {}", dest_url), &e);
+            push_errors.push(format!("{}: {}", dest_url, e.message()));
+            continue
+        }
+        // A plain `push --mirror` only moves the LFS pointer files; push the objects too.
+        if lfs {
+            if let Err(e) = lfs_push(&repo_path, &remote_name, credentials) {
+                eprintln!("Git LFS push to {} failed: {}", dest_url, e);
+                push_errors.push(format!("{} (lfs): {}", dest_url, e));
+            }
         }
     }
-    // Push to the mirror repo.
-    if let Some(e) = check_git_return(&push(&repo_path), MirrorResult::PushFailed) {
-        return Ok(e)
+    if !push_errors.is_empty() {
+        return Ok((MirrorResult::PushFailed, Some(push_errors.join("; "))))
     }
-    Ok(MirrorResult::Success)
+    Ok((MirrorResult::Success, None))
 }
 
-/// Common type for wrappers around Git commands: success and optional stdout.
-type GitCmdReturn = (bool, Option<String>);
+/// Print a git2 error with some context about the operation that failed.
+fn log_git_error(context: &str, error: &git2::Error) {
+    eprintln!("Git {} failed: {}", context, error.message());
+}
 
-/// Check a GitCmdReturn.
+/// Clone `url` into `repo_path` as a bare mirror, equivalent to `git clone --mirror`.
 ///
-/// Print errors if the command failed and return `Some(on_error)`, or return None if the command
-/// completed successfully.
-fn check_git_return(cmd_return: &GitCmdReturn, on_error: MirrorResult) -> Option<MirrorResult> {
-    match cmd_return {
-        (false, output_opt) => {
-            if let Some(output) = output_opt {
-                eprintln!("Git output:\n{}", output);
-            }
-            Some(on_error)
-        }
-        _ => None
-    }
+/// The mirror refspec has to be in place on the `origin` remote *before* `RepoBuilder` runs its
+/// initial fetch -- setting it afterwards would leave that first fetch's refs under
+/// `refs/remotes/origin/*` instead of a faithful mirror layout.
+fn clone(url: &str, repo_path: &path::Path, credentials: Option<&Credentials>) -> Result<git2::Repository, git2::Error> {
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks(credentials));
+    git2::build::RepoBuilder::new()
+        .bare(true)
+        .fetch_options(fetch_options)
+        .remote_create(|repo, name, url| {
+            let remote = repo.remote_with_fetch(name, url, "+refs/*:refs/*")?;
+            repo.config()?.set_bool(&format!("remote.{}.mirror", name), true)?;
+            Ok(remote)
+        })
+        .clone(url, repo_path)
 }
 
-/// Run a git mirror clone command.
-fn clone(url: &str, path: &path::Path, name: &str) -> GitCmdReturn {
-    let args = vec!("clone", "--mirror", url, name);
-    run_git_command_in(args, path)
+/// Update an existing mirror from its `origin` remote.
+///
+/// Prunes refs that were deleted upstream, so the mirror's ref set converges exactly to the
+/// source instead of accumulating branches/tags the source no longer has -- otherwise a later
+/// mirrored push would re-publish them to every destination.
+fn fetch(repo: &git2::Repository, credentials: Option<&Credentials>) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks(credentials));
+    fetch_options.prune(git2::FetchPrune::On);
+    remote.fetch(&["+refs/*:refs/*"], Some(&mut fetch_options), None)
 }
 
-/// Update a local repository.
-fn fetch(path: &path::Path) -> GitCmdReturn {
-    run_git_command_in(vec!("fetch"), path)
+/// Return a vector of remote names.
+fn get_remotes(repo: &git2::Repository) -> Result<Vec<String>, git2::Error> {
+    Ok(repo.remotes()?.iter().filter_map(|name| name.map(String::from)).collect())
 }
 
-/// Return a vector of remote names on success.
-fn get_remotes(path: &path::Path) -> Option<Vec<String>> {
-    let (success, stdout) = run_git_command_in(vec!("remote"), path);
-    if !success {
-        return None
-    }
-    stdout.and_then(|s| Some(s.split_whitespace().map(|ss| ss.to_string()).collect()))
+/// Set the mirror remote named `remote_name` to `url` in `repo`.
+fn add_mirror_remote(repo: &git2::Repository, remote_name: &str, url: &str) -> Result<(), git2::Error> {
+    repo.remote(remote_name, url)?;
+    Ok(())
+}
+
+/// Force-push every local ref verbatim to `remote_name`, like `git push --mirror` except that refs
+/// which exist only on `remote_name` are not deleted.
+fn push(repo: &git2::Repository, remote_name: &str, credentials: Option<&Credentials>) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote(remote_name)?;
+    let mut push_options = git2::PushOptions::new();
+    push_options.remote_callbacks(remote_callbacks(credentials));
+    remote.push(&["+refs/*:refs/*"], Some(&mut push_options))
 }
 
-/// Set the mirror remote `url` in the repository at `path`.
-fn add_mirror_remote(path: &path::Path, url: &str) -> GitCmdReturn {
-    let args = vec!("remote", "add", MIRROR_REMOTE_NAME, url);
-    run_git_command_in(args, path)
+/// Pull every LFS object from `remote_name` into the mirror at `repo_path`.
+///
+/// libgit2 (and so git2) doesn't speak the LFS batch API, so this shells out to `git-lfs` the
+/// same way the rest of Mira used to shell out to `git` itself.
+fn lfs_fetch(repo_path: &path::Path, remote_name: &str, credentials: Option<&Credentials>) -> io::Result<()> {
+    run_lfs_command(repo_path, &["fetch", "--all", remote_name], credentials)
 }
 
-/// Run a git mirror push command.
-fn push(path: &path::Path) -> GitCmdReturn {
-    let args = vec!("push", "--mirror", MIRROR_REMOTE_NAME);
-    run_git_command_in(args, path)
+/// Push every LFS object to `remote_name` from the mirror at `repo_path`.
+fn lfs_push(repo_path: &path::Path, remote_name: &str, credentials: Option<&Credentials>) -> io::Result<()> {
+    run_lfs_command(repo_path, &["push", "--all", remote_name], credentials)
 }
 
-/// Run a git command with supplied arguments, return true on successful completion.
-fn run_git_command(args: Vec<&str>) -> GitCmdReturn {
+/// Run `git lfs <args>` in `repo_path`, returning an error if the command fails to start or exits
+/// unsuccessfully.
+///
+/// `git lfs` talks to the remote through git's own HTTP/SSH stack, which never sees the
+/// `Credentials` git2 resolved for the pointer fetch/push, so a `Token` credential is re-injected
+/// as an `Authorization` header via git's config-from-environment variables. It's sent as HTTP
+/// Basic with the same `x-access-token` username the git2 push path uses (`remote_callbacks`),
+/// not Bearer, so forges that only recognize the token as a Basic password still accept it.
+/// `UserPass` and `SshKey` credentials aren't forwarded this way -- LFS transfers for those still
+/// depend on whatever credential helper or SSH agent the host already has configured.
+fn run_lfs_command(repo_path: &path::Path, args: &[&str], credentials: Option<&Credentials>) -> io::Result<()> {
     let mut command = process::Command::new("git");
-    command.args(&args);
-    match command.output() {
-        Ok(output) => {
-            let success = output.status.success();
-            let text = String::from_utf8(
-                if success { output.stdout } else { output.stderr }
-            ).ok();
-            (success, text)
-        }
-        Err(e) => { eprintln!("Failed to run Git: {}", e); (false, None) }
+    command.arg("-C").arg(repo_path);
+    if let Some(Credentials::Token { token }) = credentials {
+        let basic = base64_encode(format!("x-access-token:{}", resolve_secret(token)).as_bytes());
+        command
+            .env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+            .env("GIT_CONFIG_VALUE_0", format!("Authorization: Basic {}", basic));
+    }
+    let status = command.arg("lfs").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("git lfs {:?} exited with {}", args, status)))
     }
 }
 
-/// Call `run_git_command` but with a work directory specified.
-fn run_git_command_in(args: Vec<&str>, path: &path::Path) -> GitCmdReturn {
-    let path = match path.to_str() {
-        Some(path) => path,
-        None => { eprintln!("Invalid path: {:?}", path); return (false, None) }
-    };
-    let mut full_args = vec!("-C", path);
-    full_args.extend(args.clone());
-    run_git_command(full_args)
+/// Encode `input` as standard base64, for the LFS `Authorization: Basic` header.
+fn base64_encode(input: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        out.push(TABLE[((n >> 18) & 0x3f) as usize] as char);
+        out.push(TABLE[((n >> 12) & 0x3f) as usize] as char);
+        out.push(if chunk.len() > 1 { TABLE[((n >> 6) & 0x3f) as usize] as char } else { '=' });
+        out.push(if chunk.len() > 2 { TABLE[(n & 0x3f) as usize] as char } else { '=' });
+    }
+    out
 }